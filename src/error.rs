@@ -13,10 +13,21 @@ pub enum NetpodError {
     BendyDecoding(decoding::Error),
     #[error("bendy encoding error: {0}")]
     BendyEncoding(encoding::Error),
+    #[error("connection closed")]
+    Eof,
     #[error(transparent)]
     Other(#[from] Box<dyn std::error::Error + Send + Sync>), // Accepts any error
 }
 
+impl NetpodError {
+    /// True when the connection was closed cleanly rather than failing
+    /// mid-message, so callers can tell "client hung up" apart from a
+    /// real protocol or I/O error.
+    pub fn is_eof(&self) -> bool {
+        matches!(self, NetpodError::Eof)
+    }
+}
+
 impl From<&str> for NetpodError {
     fn from(s: &str) -> Self {
         NetpodError::Message(s.to_string())
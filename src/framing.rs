@@ -0,0 +1,185 @@
+use crate::error::NetpodError;
+use crate::Request;
+use bendy::decoding::FromBencode;
+
+/// Caps how deeply nested lists/dicts can be before a message is
+/// rejected outright, so a crafted, never-terminating nested prefix
+/// can't blow the stack via `scan`/`scan_container`'s mutual recursion.
+const MAX_NESTING_DEPTH: usize = 512;
+
+/// Caps how large a single message is allowed to be, claimed or buffered.
+/// Without this, a byte-string length (or any other construct) that never
+/// completes just makes the scanner return `None` forever, so the caller's
+/// read buffer grows without bound for as long as the peer trickles bytes
+/// — reachable over the open network since the TCP transport was added.
+const MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Scans the bencode value starting at the front of `buf` and returns
+/// its length in bytes, or `None` if `buf` does not yet hold a complete
+/// value. Unlike checking whether the last byte is `b'e'`, this walks
+/// the actual token structure, so it can tell a complete message apart
+/// from one that merely ends on a byte that looks like a terminator.
+fn bencode_value_len(buf: &[u8]) -> Result<Option<usize>, NetpodError> {
+    if buf.len() > MAX_MESSAGE_SIZE {
+        return Err(NetpodError::Message(format!(
+            "bencode message exceeds max size of {} bytes",
+            MAX_MESSAGE_SIZE
+        )));
+    }
+    scan(buf, 0, 0)
+}
+
+fn scan(buf: &[u8], pos: usize, depth: usize) -> Result<Option<usize>, NetpodError> {
+    if depth > MAX_NESTING_DEPTH {
+        return Err(NetpodError::Message(format!(
+            "bencode nesting exceeds max depth of {}",
+            MAX_NESTING_DEPTH
+        )));
+    }
+    match buf.get(pos) {
+        None => Ok(None),
+        Some(b'i') => scan_until_terminator(buf, pos + 1),
+        Some(b'l') | Some(b'd') => scan_container(buf, pos + 1, depth + 1),
+        Some(b'0'..=b'9') => scan_string(buf, pos),
+        Some(other) => Err(NetpodError::Message(format!(
+            "invalid bencode token `{}`",
+            *other as char
+        ))),
+    }
+}
+
+fn scan_until_terminator(buf: &[u8], start: usize) -> Result<Option<usize>, NetpodError> {
+    match buf[start..].iter().position(|&b| b == b'e') {
+        Some(offset) => Ok(Some(start + offset + 1)),
+        None => Ok(None),
+    }
+}
+
+fn scan_container(buf: &[u8], mut pos: usize, depth: usize) -> Result<Option<usize>, NetpodError> {
+    loop {
+        match buf.get(pos) {
+            None => return Ok(None),
+            Some(b'e') => return Ok(Some(pos + 1)),
+            Some(_) => match scan(buf, pos, depth)? {
+                Some(end) => pos = end,
+                None => return Ok(None),
+            },
+        }
+    }
+}
+
+fn scan_string(buf: &[u8], start: usize) -> Result<Option<usize>, NetpodError> {
+    let colon = match buf[start..].iter().position(|&b| b == b':') {
+        Some(offset) => start + offset,
+        None => return Ok(None),
+    };
+    let len: usize = std::str::from_utf8(&buf[start..colon])
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| NetpodError::Message("invalid bencode string length".into()))?;
+    let end = colon
+        .checked_add(1)
+        .and_then(|v| v.checked_add(len))
+        .ok_or_else(|| NetpodError::Message("bencode string length overflow".into()))?;
+    if end > MAX_MESSAGE_SIZE {
+        return Err(NetpodError::Message(format!(
+            "bencode message exceeds max size of {} bytes",
+            MAX_MESSAGE_SIZE
+        )));
+    }
+    if buf.len() < end {
+        Ok(None)
+    } else {
+        Ok(Some(end))
+    }
+}
+
+/// Incremental framer for the connection read loop. `decode` is stateless
+/// over a borrowed buffer; the caller owns the persistent buffer and
+/// drops exactly the bytes reported consumed, so leftover bytes (the
+/// start of the next pipelined request) stay put for the next call.
+pub(crate) struct Decoder;
+
+impl Decoder {
+    /// Attempts to decode a single `Request` from the front of `buf`.
+    /// Returns the request and how many bytes it consumed, or `None` if
+    /// `buf` does not yet hold a complete message.
+    pub(crate) fn decode(buf: &[u8]) -> Result<Option<(Request, usize)>, NetpodError> {
+        match bencode_value_len(buf)? {
+            Some(len) => {
+                let req = Request::from_bencode(&buf[..len]).map_err(NetpodError::from)?;
+                Ok(Some((req, len)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Op;
+
+    const PING: &[u8] = b"d2:op4:pinge";
+
+    #[test]
+    fn decode_returns_none_on_split_read() {
+        let (head, _tail) = PING.split_at(PING.len() - 3);
+        assert_eq!(Decoder::decode(head).unwrap(), None);
+    }
+
+    #[test]
+    fn decode_completes_once_the_rest_arrives() {
+        let (head, tail) = PING.split_at(PING.len() - 3);
+        assert_eq!(Decoder::decode(head).unwrap(), None);
+
+        let mut buf = head.to_vec();
+        buf.extend_from_slice(tail);
+        let (req, consumed) = Decoder::decode(&buf).unwrap().expect("now complete");
+        assert_eq!(consumed, PING.len());
+        assert_eq!(req.op, Op::Ping);
+    }
+
+    #[test]
+    fn decode_reads_one_of_two_pipelined_requests_and_leaves_the_rest() {
+        let mut buf = PING.to_vec();
+        buf.extend_from_slice(PING);
+
+        let (first, consumed) = Decoder::decode(&buf).unwrap().expect("first request");
+        assert_eq!(consumed, PING.len());
+        assert_eq!(first.op, Op::Ping);
+
+        let (second, consumed) = Decoder::decode(&buf[consumed..]).unwrap().expect("second request");
+        assert_eq!(consumed, PING.len());
+        assert_eq!(second.op, Op::Ping);
+    }
+
+    #[test]
+    fn decode_rejects_nesting_past_the_depth_cap() {
+        let buf = b"l".repeat(MAX_NESTING_DEPTH + 2);
+        let err = Decoder::decode(&buf).unwrap_err();
+        assert!(matches!(err, NetpodError::Message(msg) if msg.contains("nesting")));
+    }
+
+    #[test]
+    fn decode_rejects_a_string_length_that_overflows_usize() {
+        // usize::MAX - 1, so `colon + 1 + len` overflows the addition.
+        let buf = b"18446744073709551614:";
+        let err = Decoder::decode(buf).unwrap_err();
+        assert!(matches!(err, NetpodError::Message(msg) if msg.contains("overflow")));
+    }
+
+    #[test]
+    fn decode_rejects_a_claimed_string_length_over_the_message_size_cap() {
+        let buf = format!("{}:", MAX_MESSAGE_SIZE + 1).into_bytes();
+        let err = Decoder::decode(&buf).unwrap_err();
+        assert!(matches!(err, NetpodError::Message(msg) if msg.contains("exceeds max size")));
+    }
+
+    #[test]
+    fn decode_rejects_a_buffer_already_over_the_message_size_cap() {
+        let buf = vec![b'0'; MAX_MESSAGE_SIZE + 1];
+        let err = Decoder::decode(&buf).unwrap_err();
+        assert!(matches!(err, NetpodError::Message(msg) if msg.contains("exceeds max size")));
+    }
+}
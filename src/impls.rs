@@ -1,5 +1,6 @@
 use super::{
-    DescribeResponse, ErrorResponse, InvokeResponse, Namespace, Op, Request, Response, Status, Var,
+    DescribeResponse, ErrorDetail, ErrorResponse, InvokeResponse, Namespace, Op, PongResponse,
+    Request, Response, ShutdownResponse, Status, Var,
 };
 use bendy::decoding::{Error as BdecodeError, FromBencode, Object, ResultExt};
 use bendy::encoding::{AsString, Error as BencodeError, SingleItemEncoder, ToBencode};
@@ -10,6 +11,7 @@ impl FromBencode for Request {
         let mut id = None;
         let mut var = None;
         let mut args = None;
+        let mut trace = None;
         let mut dict = object.try_into_dictionary()?;
         while let Some(pair) = dict.next_pair()? {
             match pair {
@@ -28,6 +30,11 @@ impl FromBencode for Request {
                         .context("args")
                         .map(Some)?;
                 }
+                (b"trace", value) => {
+                    trace = AsString::decode_bencode_object(value)
+                        .context("trace")
+                        .map(|AsString(bytes)| Some(bytes))?;
+                }
                 (b"op", value) => {
                     let op_str = String::decode_bencode_object(value).context("op")?;
                     match Op::from_str(&op_str) {
@@ -45,7 +52,13 @@ impl FromBencode for Request {
             }
         }
 
-        Ok(Request { args, id, op, var })
+        Ok(Request {
+            args,
+            id,
+            op,
+            var,
+            trace,
+        })
     }
 }
 
@@ -85,41 +98,99 @@ impl ToBencode for DescribeResponse {
 }
 
 impl ToBencode for Status {
-    const MAX_DEPTH: usize = 0;
+    const MAX_DEPTH: usize = 1;
 
+    // Encoded as a list rather than a bare string so clients can tell an
+    // intermediate message (`[]`) apart from the terminal one (`["done"]`
+    // or `["error"]`) without inspecting anything else on the response.
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), BencodeError> {
-        encoder.emit_str(self.as_str())
+        encoder.emit_list(|e| {
+            if !matches!(self, Status::InProgress) {
+                e.emit_str(self.as_str())?;
+            }
+            Ok(())
+        })
     }
 }
 
 impl ToBencode for ErrorResponse {
-    const MAX_DEPTH: usize = 1;
+    const MAX_DEPTH: usize = 2;
 
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), BencodeError> {
         encoder.emit_dict(|mut e| {
+            if let Some(data) = &self.ex_data {
+                e.emit_pair(b"ex-data", AsString(data))?;
+            }
             e.emit_pair(b"ex-message", &self.ex_message)?;
             if let Some(rid) = &self.id {
                 e.emit_pair(b"id", rid)?;
             }
             e.emit_pair(b"status", &self.status)?;
+            if let Some(trace) = &self.trace {
+                e.emit_pair(b"trace", AsString(trace))?;
+            }
             Ok(())
         })
     }
 }
 
 impl ToBencode for InvokeResponse {
-    const MAX_DEPTH: usize = 1;
+    const MAX_DEPTH: usize = 2;
 
     fn encode(&self, encoder: SingleItemEncoder) -> Result<(), BencodeError> {
         encoder.emit_dict(|mut e| {
             e.emit_pair(b"id", &self.id)?;
             e.emit_pair(b"status", &self.status)?;
+            if let Some(trace) = &self.trace {
+                e.emit_pair(b"trace", AsString(trace))?;
+            }
             e.emit_pair(b"value", AsString(&self.value))?;
             Ok(())
         })
     }
 }
 
+impl ToBencode for ErrorDetail<'_> {
+    const MAX_DEPTH: usize = 1;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), BencodeError> {
+        encoder.emit_dict(|mut e| {
+            e.emit_pair(b"category", self.category)?;
+            e.emit_pair(b"retryable", if self.retryable { 1i64 } else { 0i64 })?;
+            e.emit_pair(b"var", self.var)?;
+            Ok(())
+        })
+    }
+}
+
+impl ToBencode for PongResponse {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), BencodeError> {
+        encoder.emit_dict(|mut e| {
+            if let Some(rid) = &self.id {
+                e.emit_pair(b"id", rid)?;
+            }
+            e.emit_pair(b"status", &self.status)?;
+            Ok(())
+        })
+    }
+}
+
+impl ToBencode for ShutdownResponse {
+    const MAX_DEPTH: usize = 2;
+
+    fn encode(&self, encoder: SingleItemEncoder) -> Result<(), BencodeError> {
+        encoder.emit_dict(|mut e| {
+            if let Some(rid) = &self.id {
+                e.emit_pair(b"id", rid)?;
+            }
+            e.emit_pair(b"status", &self.status)?;
+            Ok(())
+        })
+    }
+}
+
 impl ToBencode for Response {
     const MAX_DEPTH: usize = 6;
 
@@ -128,6 +199,147 @@ impl ToBencode for Response {
             Self::Error(r) => enc.emit(r),
             Self::Describe(r) => enc.emit(r),
             Self::Invoke(r) => enc.emit(r),
+            Self::Pong(r) => enc.emit(r),
+            Self::Shutdown(r) => enc.emit(r),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bendy::decoding::Decoder;
+
+    /// Encodes `value` and returns the top-level dict's keys in the order
+    /// they were written. Bendy requires dict keys sorted byte-wise, so a
+    /// key-ordering regression makes encoding fail and this panics; a key
+    /// that is missing or unexpectedly present shows up as a diff instead.
+    fn encoded_dict_keys<T: ToBencode>(value: &T) -> Vec<Vec<u8>> {
+        let bytes = value.to_bencode().expect("encodes");
+        let mut decoder = Decoder::new(&bytes);
+        let mut dict = match decoder.next_object().unwrap().unwrap() {
+            Object::Dict(d) => d,
+            other => panic!("expected a dict, got {:?}", other.into_token()),
+        };
+        let mut keys = Vec::new();
+        while let Some((k, _)) = dict.next_pair().unwrap() {
+            keys.push(k.to_vec());
         }
+        keys
+    }
+
+    #[test]
+    fn status_encodes_as_a_list_done_vs_in_progress() {
+        assert_eq!(Status::InProgress.to_bencode().unwrap(), b"le");
+        assert_eq!(Status::Done.to_bencode().unwrap(), b"l4:donee");
+        assert_eq!(Status::Error.to_bencode().unwrap(), b"l5:errore");
+        assert_eq!(Status::Pong.to_bencode().unwrap(), b"l4:ponge");
+    }
+
+    #[test]
+    fn error_response_with_ex_data_encodes_sorted_keys() {
+        // Regression test: ex-data must be emitted before ex-message
+        // (and trace last), since bendy rejects at encode time any dict
+        // whose keys are not emitted in sorted byte order.
+        let r = ErrorResponse {
+            id: Some("1".to_string()),
+            status: Status::Error,
+            ex_message: "boom".to_string(),
+            ex_data: Some(b"detail".to_vec()),
+            trace: Some(b"trace-id".to_vec()),
+        };
+        assert_eq!(
+            encoded_dict_keys(&r),
+            vec![
+                b"ex-data".to_vec(),
+                b"ex-message".to_vec(),
+                b"id".to_vec(),
+                b"status".to_vec(),
+                b"trace".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_response_without_ex_data_or_trace_omits_those_keys() {
+        let r = ErrorResponse {
+            id: None,
+            status: Status::Error,
+            ex_message: "boom".to_string(),
+            ex_data: None,
+            trace: None,
+        };
+        assert_eq!(
+            encoded_dict_keys(&r),
+            vec![b"ex-message".to_vec(), b"status".to_vec()]
+        );
+    }
+
+    #[test]
+    fn invoke_response_encodes_sorted_keys() {
+        let r = InvokeResponse {
+            id: "1".to_string(),
+            status: Status::Done,
+            value: b"42".to_vec(),
+            is_final: true,
+            trace: Some(b"trace-id".to_vec()),
+        };
+        assert_eq!(
+            encoded_dict_keys(&r),
+            vec![
+                b"id".to_vec(),
+                b"status".to_vec(),
+                b"trace".to_vec(),
+                b"value".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn pong_response_encodes_sorted_keys() {
+        let r = PongResponse {
+            id: Some("1".to_string()),
+            status: Status::Pong,
+        };
+        assert_eq!(
+            encoded_dict_keys(&r),
+            vec![b"id".to_vec(), b"status".to_vec()]
+        );
+    }
+
+    #[test]
+    fn shutdown_response_encodes_sorted_keys() {
+        let r = ShutdownResponse {
+            id: None,
+            status: Status::Done,
+        };
+        assert_eq!(encoded_dict_keys(&r), vec![b"status".to_vec()]);
+    }
+
+    #[test]
+    fn error_detail_encodes_sorted_keys() {
+        let d = ErrorDetail {
+            category: "not_found",
+            var: "ns/foo",
+            retryable: false,
+        };
+        assert_eq!(
+            encoded_dict_keys(&d),
+            vec![b"category".to_vec(), b"retryable".to_vec(), b"var".to_vec()]
+        );
+    }
+
+    #[test]
+    fn request_decodes_trace_as_raw_bytes() {
+        let bytes: &[u8] = b"d2:op6:invoke5:trace3:tide";
+        let req = Request::from_bencode(bytes).expect("decodes");
+        assert_eq!(req.op, Op::Invoke);
+        assert_eq!(req.trace, Some(b"tid".to_vec()));
+    }
+
+    #[test]
+    fn request_rejects_unknown_fields() {
+        let bytes: &[u8] = b"d7:bogus1:xe";
+        assert!(Request::from_bencode(bytes).is_err());
     }
 }
@@ -1,22 +1,25 @@
-use bendy::decoding::FromBencode;
 use bendy::encoding::ToBencode;
 use std::collections::HashMap;
-use std::future::Future;
 use std::pin::Pin;
 use std::result::Result;
 use std::sync::Arc;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{UnixListener, UnixStream},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, ToSocketAddrs, UnixListener},
 };
+use tokio_stream::{Stream, StreamExt};
 pub mod error;
+mod framing;
 mod impls;
 use error::NetpodError;
+use framing::Decoder;
 
 #[derive(Debug, PartialEq)]
 enum Op {
     Describe,
     Invoke,
+    Ping,
+    Shutdown,
 }
 
 impl Op {
@@ -24,6 +27,8 @@ impl Op {
         match s {
             "describe" => Ok(Op::Describe),
             "invoke" => Ok(Op::Invoke),
+            "ping" => Ok(Op::Ping),
+            "shutdown" => Ok(Op::Shutdown),
             _ => Err(format!("Invalid operation: {}", s)),
         }
     }
@@ -35,6 +40,10 @@ pub struct Request {
     pub id: Option<String>,
     var: Option<String>,
     pub args: Option<String>,
+    /// Opaque trace context (e.g. a serialized OpenTelemetry span
+    /// context) propagated by the client so a handler can start a child
+    /// span. Fully optional; absent when the client doesn't send one.
+    pub trace: Option<Vec<u8>>,
 }
 
 #[derive(PartialEq, Debug)]
@@ -56,40 +65,94 @@ pub struct DescribeResponse {
 
 #[derive(Debug, PartialEq)]
 pub enum Status {
+    InProgress,
     Done,
     Error,
+    Pong,
 }
 
 impl Status {
     fn as_str(&self) -> &str {
         match self {
+            Self::InProgress => "",
             Self::Done => "done",
             Self::Error => "error",
+            Self::Pong => "pong",
         }
     }
 }
 
+#[derive(Debug, PartialEq)]
+pub struct PongResponse {
+    id: Option<String>,
+    status: Status,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct ErrorResponse {
     id: Option<String>,
     status: Status,
     ex_message: String,
-    //ex_data: Option<String>,
+    ex_data: Option<Vec<u8>>,
+    trace: Option<Vec<u8>>,
+}
+
+/// Structured detail attached to an error's `ex-data` so clients can
+/// branch on the failure programmatically instead of pattern-matching
+/// `ex-message`. `category` identifies the kind of failure (e.g.
+/// `"not_found"` vs `"handler_error"`); `retryable` reflects whether that
+/// particular failure is worth retrying.
+struct ErrorDetail<'a> {
+    category: &'static str,
+    var: &'a str,
+    retryable: bool,
+}
+
+pub fn err_response(id: Option<String>, err: NetpodError, trace: Option<Vec<u8>>) -> Response {
+    Response::Error(ErrorResponse {
+        id,
+        status: Status::Error,
+        ex_message: err.to_string(),
+        ex_data: None,
+        trace,
+    })
 }
 
-pub fn err_response(id: Option<String>, err: NetpodError) -> Response {
+pub fn err_response_with_data(
+    id: Option<String>,
+    err: NetpodError,
+    ex_data: Vec<u8>,
+    trace: Option<Vec<u8>>,
+) -> Response {
     Response::Error(ErrorResponse {
         id,
         status: Status::Error,
         ex_message: err.to_string(),
+        ex_data: Some(ex_data),
+        trace,
     })
 }
 
-pub fn invoke_response(id: String, value: Vec<u8>) -> Response {
+/// Builds a single invoke response message.
+///
+/// Set `is_final` to `false` to emit an intermediate message (e.g. a
+/// progress update or a generator item) and `true` for the last message
+/// on the stream, which is the only one that carries a `done` status.
+/// `trace` should be the originating request's trace context, if any, so
+/// it can be propagated back to the caller.
+pub fn invoke_response(
+    id: String,
+    value: Vec<u8>,
+    is_final: bool,
+    trace: Option<Vec<u8>>,
+) -> Response {
+    let status = if is_final { Status::Done } else { Status::InProgress };
     let r = InvokeResponse {
         id,
-        status: Status::Done,
+        status,
         value,
+        is_final,
+        trace,
     };
     Response::Invoke(r)
 }
@@ -99,6 +162,14 @@ pub struct InvokeResponse {
     id: String,
     status: Status,
     value: Vec<u8>,
+    is_final: bool,
+    trace: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ShutdownResponse {
+    id: Option<String>,
+    status: Status,
 }
 
 #[derive(Debug)]
@@ -106,48 +177,60 @@ pub enum Response {
     Describe(DescribeResponse),
     Invoke(InvokeResponse),
     Error(ErrorResponse),
+    Pong(PongResponse),
+    Shutdown(ShutdownResponse),
 }
 
-async fn read_request(stream: &mut UnixStream) -> Result<Request, NetpodError> {
-    let mut buffer = [0; 1024 * 2];
-    let mut data = Vec::new();
-    let req: Option<Request>;
+impl Response {
+    /// True when this is the last message a caller should expect for the
+    /// request it answers. Only a non-final `Invoke` response (an
+    /// intermediate item on a streaming handler) is not final.
+    fn is_final(&self) -> bool {
+        match self {
+            Self::Invoke(r) => r.is_final,
+            Self::Describe(_) | Self::Error(_) | Self::Pong(_) | Self::Shutdown(_) => true,
+        }
+    }
+}
 
-    loop {
-        let bytes_read = stream.read(&mut buffer).await?;
+/// Pulls the next request off `stream`, using `buf` as the connection's
+/// persistent read buffer. Bytes left over after a complete message is
+/// decoded (e.g. the start of a pipelined request) stay in `buf` for the
+/// next call, so this never re-reads from the socket when a full
+/// message is already buffered.
+async fn next_request<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut Vec<u8>,
+) -> Result<Request, NetpodError> {
+    let mut chunk = [0; 1024 * 2];
 
-        if bytes_read == 0 {
-            req = Some(decode_request(&data)?);
-            break; // End of stream reached
+    loop {
+        if let Some((req, consumed)) = Decoder::decode(buf)? {
+            buf.drain(..consumed);
+            return Ok(req);
         }
 
-        // Append the read data
-        data.extend_from_slice(&buffer[..bytes_read]);
-
-        match decode_request(&data) {
-            Ok(r) => {
-                req = Some(r);
-                break;
+        let bytes_read = stream.read(&mut chunk).await?;
+        if bytes_read == 0 {
+            if buf.is_empty() {
+                // clean close between requests, not a broken message
+                return Err(NetpodError::Eof);
             }
-            Err(_e) => continue,
+            return Err("connection closed mid-message".into());
         }
-    }
-
-    req.ok_or("request is None".into())
-}
 
-fn decode_request(buffer: &[u8]) -> Result<Request, NetpodError> {
-    // Check if the last byte is `e` (ASCII value for 'e') which marks dictionary termination
-    if buffer[buffer.len() - 1] == b'e' {
-        Request::from_bencode(buffer).map_err(NetpodError::from)
-    } else {
-        Err("keep reading".into())
+        buf.extend_from_slice(&chunk[..bytes_read]);
     }
 }
 
-pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Response, NetpodError>> + Send>>;
+/// A stream of invocation results. Handlers that only ever produce a
+/// single value can build one with `tokio_stream::once`; handlers that
+/// want to stream progress updates or generator items can use
+/// `async_stream::stream!` (or any other `Stream` combinator) and end
+/// the stream with a response built via `invoke_response(.., true)`.
+pub type HandlerStream = Pin<Box<dyn Stream<Item = Result<Response, NetpodError>> + Send>>;
 
-pub type HandlerFn = Box<dyn Fn(Request) -> HandlerFuture + Send + Sync>;
+pub type HandlerFn = Box<dyn Fn(Request) -> HandlerStream + Send + Sync>;
 
 pub async fn run_server(
     socket_path: &str,
@@ -167,57 +250,122 @@ pub async fn run_server(
     }
 }
 
-async fn handle_client(mut stream: UnixStream, handler_map: Arc<HashMap<String, HandlerFn>>) {
-    let request = read_request(&mut stream).await;
-
-    match request {
-        Ok(req) => {
-            let response = handle_request(handler_map, req).await;
-            match response {
-                Ok(response) => match response.to_bencode() {
-                    Ok(buf) => {
-                        if let Err(err) = stream.write_all(&buf).await {
-                            eprintln!("writing out stream failed {}", err);
-                        }
-                    }
-                    Err(err) => {
-                        let er = err_response(None, err.into());
-                        if let Ok(e_buf) = er.to_bencode() {
-                            if let Err(err) = stream.write_all(&e_buf).await {
-                                eprintln!("failed writing out err stream {}", err);
+/// Same protocol as `run_server`, but reachable over TCP instead of a
+/// local Unix socket, e.g. to run the pod as a sidecar on another host.
+pub async fn run_server_tcp<A: ToSocketAddrs>(
+    addr: A,
+    handler_map: HashMap<String, HandlerFn>,
+) -> Result<(), NetpodError> {
+    let listener = TcpListener::bind(addr).await?;
+    let handlers = Arc::new(handler_map);
+
+    loop {
+        let (stream, _addr) = listener.accept().await?;
+
+        let hm = handlers.clone();
+        tokio::spawn(async move { handle_client(stream, hm).await });
+    }
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    response: &Response,
+) -> Result<(), NetpodError> {
+    let buf = response.to_bencode()?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    handler_map: Arc<HashMap<String, HandlerFn>>,
+) {
+    let mut buf = Vec::new();
+    loop {
+        let req = match next_request(&mut stream, &mut buf).await {
+            Ok(req) => req,
+            Err(e) => {
+                if !e.is_eof() {
+                    eprintln!("trouble reading request from the stream {}", e);
+                }
+                break;
+            }
+        };
+
+        let is_shutdown = req.op == Op::Shutdown;
+
+        match handle_request(handler_map.clone(), req) {
+            Dispatch::Single(response) => {
+                if let Err(err) = write_response(&mut stream, &response).await {
+                    eprintln!("writing out stream failed {}", err);
+                    break;
+                }
+            }
+            Dispatch::Stream(var_name, trace, mut responses) => {
+                while let Some(item) = responses.next().await {
+                    let response = match item {
+                        Ok(response) => response,
+                        Err(e) => {
+                            eprintln!("handler for {} failed with `{}`", var_name, e);
+                            let retryable = matches!(e, NetpodError::IO(_));
+                            let detail = ErrorDetail {
+                                category: "handler_error",
+                                var: &var_name,
+                                retryable,
+                            };
+                            match detail.to_bencode() {
+                                Ok(data) => err_response_with_data(None, e, data, trace.clone()),
+                                Err(_) => err_response(None, e, trace.clone()),
                             }
                         }
+                    };
+                    let is_final = response.is_final();
+                    if let Err(err) = write_response(&mut stream, &response).await {
+                        eprintln!("writing out stream failed {}", err);
+                        // The write itself failed, so the connection is
+                        // unusable; end it the same way the Single arm does
+                        // rather than looping back into next_request.
+                        return;
                     }
-                },
-                Err(e) => {
-                    eprintln!("handle_request failed with `{}`", e);
-                    let er = err_response(None, e);
-                    match er.to_bencode() {
-                        Ok(e_buf) => {
-                            if let Err(err) = stream.write_all(&e_buf).await {
-                                eprintln!("failed writing out stream {}", err);
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!("trouble encoding error response {}", err);
-                        }
+                    // A terminal response (error or final invoke item) ends
+                    // the call; stop polling even if the stream itself has
+                    // more items, so stray post-terminal bytes can't desync
+                    // the next pipelined request.
+                    if is_final {
+                        break;
                     }
                 }
             }
         }
-        Err(e) => {
-            eprintln!("trouble reading request from the stream {}", e);
+
+        if is_shutdown {
+            break;
         }
     }
 }
 
-async fn handle_request(
-    handler_map: Arc<HashMap<String, HandlerFn>>,
-    req: Request,
-) -> Result<Response, NetpodError> {
+/// What a dispatched request turns into: a single response (describe,
+/// ping, shutdown, or an invoke that failed before reaching a handler) or a stream of
+/// responses produced by a handler.
+enum Dispatch {
+    Single(Response),
+    Stream(String, Option<Vec<u8>>, HandlerStream),
+}
+
+fn handle_request(handler_map: Arc<HashMap<String, HandlerFn>>, req: Request) -> Dispatch {
     match req.op {
-        Op::Describe => handle_describe(handler_map),
-        Op::Invoke => handle_invoke(handler_map, req).await,
+        Op::Describe => Dispatch::Single(
+            handle_describe(handler_map).unwrap_or_else(|e| err_response(None, e, None)),
+        ),
+        Op::Invoke => handle_invoke(handler_map, req),
+        Op::Ping => Dispatch::Single(Response::Pong(PongResponse {
+            id: req.id,
+            status: Status::Pong,
+        })),
+        Op::Shutdown => Dispatch::Single(Response::Shutdown(ShutdownResponse {
+            id: req.id,
+            status: Status::Done,
+        })),
     }
 }
 
@@ -257,25 +405,32 @@ fn handle_describe(handler_map: Arc<HashMap<String, HandlerFn>>) -> Result<Respo
     Ok(Response::Describe(r))
 }
 
-async fn handle_invoke(
-    handler_map: Arc<HashMap<String, HandlerFn>>,
-    req: Request,
-) -> Result<Response, NetpodError> {
+fn handle_invoke(handler_map: Arc<HashMap<String, HandlerFn>>, req: Request) -> Dispatch {
     if let Some(var_name) = &req.var {
         if let Some(func) = handler_map.get(var_name) {
-            func(req).await
+            let var_name = var_name.clone();
+            let trace = req.trace.clone();
+            let stream = func(req);
+            Dispatch::Stream(var_name, trace, stream)
         } else {
             eprintln!("handler for {} not found", var_name);
-            Ok(err_response(
-                req.id,
-                NetpodError::Message(format!("error no handler for {}", var_name)),
-            ))
+            let detail = ErrorDetail {
+                category: "not_found",
+                var: var_name,
+                retryable: false,
+            };
+            let err = NetpodError::Message(format!("error no handler for {}", var_name));
+            Dispatch::Single(match detail.to_bencode() {
+                Ok(data) => err_response_with_data(req.id, err, data, req.trace),
+                Err(_) => err_response(req.id, err, req.trace),
+            })
         }
     } else {
         eprintln!("request lacks var {:?}", &req);
-        Ok(err_response(
+        Dispatch::Single(err_response(
             req.id,
             NetpodError::Message("request lacks var name".into()),
+            req.trace,
         ))
     }
 }